@@ -1,9 +1,14 @@
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
 use anyhow::{anyhow, bail, Error};
 use argh::FromArgs;
 use reqwest::blocking::{Client, Response};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 const SERVER_ENVVAR: &str = "BEELAY_SERVER";
+const CONFIG_FILE_NAME: &str = "beelay-cli.toml";
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Beelay CLI client
@@ -13,7 +18,61 @@ struct Args {
 
     #[argh(option, short = 's', long = "server")]
     /// beelay server address
-    server: Option<String>
+    server: Option<String>,
+
+    #[argh(option, short = 'p', long = "profile")]
+    /// named server profile from the config file
+    profile: Option<String>,
+
+    #[argh(switch, short = 'd', long = "debug")]
+    /// print request URLs and raw response bodies
+    debug: bool,
+
+    #[argh(option, long = "token")]
+    /// bearer token to authenticate with
+    token: Option<String>,
+
+    #[argh(option, long = "output", default = "OutputFormat::Human")]
+    /// output format: "human" (default) or "json"
+    output: OutputFormat
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum OutputFormat {
+    Human,
+    Json
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(OutputFormat::Human),
+            "json" => Ok(OutputFormat::Json),
+            other => Err(format!("unknown output format \"{other}\" (expected \"human\" or \"json\")"))
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct Config {
+    /// default server address
+    server: Option<String>,
+
+    /// bearer token to authenticate with
+    token: Option<String>,
+
+    /// RFC 3339 timestamp after which `token` is no longer valid
+    not_after: Option<String>,
+
+    #[serde(default)]
+    profiles: HashMap<String, ProfileConfig>
+}
+
+#[derive(Deserialize)]
+struct ProfileConfig {
+    server: String
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -21,7 +80,9 @@ struct Args {
 enum SubCommands {
     Get(GetCommand),
     Set(SetCommand),
-    List(ListCommand)
+    List(ListCommand),
+    Watch(WatchCommand),
+    Health(HealthCommand)
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -46,7 +107,7 @@ struct SetCommand {
     state: String,
 
     #[argh(option, short = 'd', long = "delay")]
-    /// state change delay
+    /// state change delay: a relative duration ("30s", "5m", "1h30m") or an absolute RFC 3339 timestamp
     delay: Option<String>
 }
 
@@ -55,7 +116,29 @@ struct SetCommand {
 #[argh(subcommand, name = "list")]
 struct ListCommand { }
 
-#[derive(Deserialize)]
+#[derive(FromArgs, PartialEq, Debug)]
+/// poll a switch until it stops transitioning
+#[argh(subcommand, name = "watch")]
+struct WatchCommand {
+    #[argh(positional)]
+    /// switch name
+    switch_name: String,
+
+    #[argh(option, long = "interval", default = "500")]
+    /// poll interval in milliseconds
+    interval_ms: u64,
+
+    #[argh(option, long = "timeout")]
+    /// give up and fail after this many seconds
+    timeout_secs: Option<u64>
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// check that the server is up
+#[argh(subcommand, name = "health")]
+struct HealthCommand { }
+
+#[derive(Deserialize, Serialize)]
 struct SwitchStateResponse {
     // pub _status: String,
     pub state: String,
@@ -75,92 +158,370 @@ struct SwitchesResponse {
     // pub _filters: HashMap<String, Vec<String>>
 }
 
+#[derive(Serialize)]
+struct ErrorOutput {
+    error: String,
+    status: Option<u16>
+}
+
+#[derive(Serialize)]
+struct HealthOutput {
+    up: bool,
+    status: u16
+}
+
+/// An error carrying the HTTP status code it originated from, if any, so
+/// `--output json` can report it without every call site having to thread
+/// output formatting through its error path.
+#[derive(Debug)]
+struct StatusError {
+    message: String,
+    status: Option<u16>
+}
+
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for StatusError { }
+
 fn main() {
     let args: Args = argh::from_env();
+    let output = args.output;
 
-    let server_addr = 
-        fix_server_addr(
-            match args.server {
-                Some(server) => server,
-                None => match std::env::var(SERVER_ENVVAR) {
-                    Ok(server) => server,
-                    Err(_) => "http://localhost:9999".to_string()
+    if let Err(err) = run(args) {
+        match output {
+            OutputFormat::Human => {
+                eprintln!("Error during beelay request:");
+                eprintln!("    {err}")
+            }
+            OutputFormat::Json => {
+                let status = err.downcast_ref::<StatusError>().and_then(|err| err.status);
+                let err_out = ErrorOutput { error: err.to_string(), status };
+                match serde_json::to_string(&err_out) {
+                    Ok(json) => println!("{json}"),
+                    Err(_) => {
+                        eprintln!("Error during beelay request:");
+                        eprintln!("    {err}")
+                    }
                 }
             }
-        );
+        }
+        std::process::exit(1);
+    }
+}
+
+fn run(args: Args) -> Result<(), Error> {
+    let config = load_config()?;
+    let server_addr = resolve_server_addr(args.server, args.profile, &config)?;
+    let token = resolve_token(args.token, &config)?;
+    let output = args.output;
 
     let client = Client::new();
-    let res = match args.command {
-        SubCommands::Get(args) => get_switch(client, server_addr, args.switch_name),
-        SubCommands::Set(args) => set_switch(client, server_addr, args.switch_name, args.state),
-        SubCommands::List(_) => list_switches(client, server_addr),
+    match args.command {
+        SubCommands::Get(cmd) => get_switch(client, server_addr, cmd.switch_name, token, args.debug, output),
+        SubCommands::Set(cmd) => set_switch(client, server_addr, cmd.switch_name, cmd.state, cmd.delay, token, args.debug, output),
+        SubCommands::List(_) => list_switches(client, server_addr, token, args.debug, output),
+        SubCommands::Watch(cmd) => watch_switch(client, server_addr, cmd.switch_name, cmd.interval_ms, cmd.timeout_secs, token, args.debug, output),
+        SubCommands::Health(_) => check_health(client, server_addr, token, args.debug, output),
+    }
+}
+
+fn load_config() -> Result<Config, Error> {
+    let path = match dirs::config_dir() {
+        Some(dir) => dir.join(CONFIG_FILE_NAME),
+        None => return Ok(Config::default())
+    };
+
+    if !path.exists() {
+        return Ok(Config::default());
+    }
+
+    let text = std::fs::read_to_string(&path)
+        .map_err(|err| anyhow!("Failed to read config file {}: {}", path.display(), err))?;
+
+    toml::from_str(&text)
+        .map_err(|err| anyhow!("Failed to parse config file {}: {}", path.display(), err))
+}
+
+fn resolve_server_addr(server: Option<String>, profile: Option<String>, config: &Config) -> Result<String, Error> {
+    if let Some(server) = server {
+        return Ok(fix_server_addr(server));
+    }
+
+    if let Some(name) = profile {
+        let server = config.profiles.get(&name)
+            .ok_or_else(|| anyhow!("No such profile: {name}"))?
+            .server.clone();
+
+        return Ok(fix_server_addr(server));
+    }
+
+    if let Ok(server) = std::env::var(SERVER_ENVVAR) {
+        return Ok(fix_server_addr(server));
+    }
+
+    let server = config.server.clone().unwrap_or_else(|| "http://localhost:9999".to_string());
+
+    Ok(fix_server_addr(server))
+}
+
+fn resolve_token(token: Option<String>, config: &Config) -> Result<Option<String>, Error> {
+    if let Some(token) = token {
+        return Ok(Some(token));
+    }
+
+    let token = match &config.token {
+        Some(token) => token.clone(),
+        None => return Ok(None)
     };
 
-    if let Err(err) = res {
-        eprintln!("Error during beelay request:");
-        eprintln!("    {err}")
+    if let Some(not_after) = &config.not_after {
+        let not_after = humantime::parse_rfc3339_weak(not_after)
+            .map_err(|err| anyhow!("Failed to parse token expiry \"{not_after}\": {err}"))?;
+
+        if SystemTime::now() >= not_after {
+            bail!("token expired on {}", humantime::format_rfc3339(not_after));
+        }
     }
+
+    Ok(Some(token))
 }
 
-fn get_switch(client: Client, server_addr: String, switch_name: String) -> Result<(), Error> {
-    let resp = 
-        client.get(to_switch_url(server_addr, switch_name))
-            .send()?;
+fn get_switch(client: Client, server_addr: String, switch_name: String, token: Option<String>, debug: bool, output: OutputFormat) -> Result<(), Error> {
+    let url = to_switch_url(server_addr, switch_name);
+    if debug {
+        eprintln!("[debug] GET {url}");
+    }
+
+    let mut req = client.get(&url);
+    if let Some(token) = token {
+        req = req.bearer_auth(token);
+    }
+
+    let resp = req.send()?;
 
     if !resp.status().is_success() {
         handle_bad_status_code(resp)
     }
     else {
-        print_switch_state_response(resp)
+        print_switch_state_response(resp, debug, output)
     }
 }
 
-fn set_switch(client: Client, server_addr: String, switch_name: String, state: String) -> Result<(), Error> {
-    let resp = 
-        client.post(to_switch_url(server_addr, switch_name))
-            .query(&[("state", &state)])
-            .send()?;
+fn set_switch(client: Client, server_addr: String, switch_name: String, state: String, delay: Option<String>, token: Option<String>, debug: bool, output: OutputFormat) -> Result<(), Error> {
+    let delay_secs = match delay {
+        Some(raw) => Some(parse_delay(&raw)?.as_secs()),
+        None => None
+    };
+
+    let url = to_switch_url(server_addr, switch_name);
+    if debug {
+        match delay_secs {
+            Some(delay_secs) => eprintln!("[debug] POST {url}?state={state}&delay={delay_secs}"),
+            None => eprintln!("[debug] POST {url}?state={state}")
+        }
+    }
+
+    let mut req = client.post(&url).query(&[("state", &state)]);
+    if let Some(delay_secs) = delay_secs {
+        req = req.query(&[("delay", delay_secs)]);
+    }
+    if let Some(token) = token {
+        req = req.bearer_auth(token);
+    }
+
+    let resp = req.send()?;
 
     if !resp.status().is_success() {
         handle_bad_status_code(resp)
     }
     else {
-        print_switch_state_response(resp)
+        if let Some(delay_secs) = delay_secs {
+            match output {
+                OutputFormat::Human => println!("effective delay: {delay_secs}s"),
+                OutputFormat::Json => eprintln!("effective delay: {delay_secs}s")
+            }
+        }
+
+        print_switch_state_response(resp, debug, output)
     }
 }
 
-fn print_switch_state_response(resp: Response) -> Result<(), Error> {
+fn parse_delay(raw: &str) -> Result<Duration, Error> {
+    if let Ok(duration) = humantime::parse_duration(raw) {
+        return Ok(duration);
+    }
+
+    let when = humantime::parse_rfc3339_weak(raw)
+        .map_err(|_| anyhow!(
+            "Could not parse \"{raw}\" as a relative duration (e.g. \"30s\", \"5m\", \"1h30m\") or an absolute RFC 3339 timestamp"
+        ))?;
+
+    when.duration_since(SystemTime::now())
+        .map_err(|_| anyhow!("Delay \"{raw}\" is in the past"))
+}
+
+fn print_switch_state_response(resp: Response, debug: bool, output: OutputFormat) -> Result<(), Error> {
+    let status = resp.status();
     let text = resp.text()?;
+
+    if debug {
+        eprintln!("[debug] status: {status}");
+        eprintln!("[debug] body: {text}");
+    }
+
     let resp: SwitchStateResponse = serde_json::from_str(&text)
         .map_err(|err| anyhow!("Failed to parse response: {}", err))?;
 
-    println!("state         : {}", resp.state);
-    println!("transitioning : {}", resp.transitioning);
+    match output {
+        OutputFormat::Human => {
+            println!("state         : {}", resp.state);
+            println!("transitioning : {}", resp.transitioning);
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string(&resp)?)
+    }
 
     Ok(())
 }
 
-fn list_switches(client: Client, server_addr: String) -> Result<(), Error> {
-    let resp = 
-        client.get(to_switches_url(server_addr))
-            .send()?;
-    
+fn list_switches(client: Client, server_addr: String, token: Option<String>, debug: bool, output: OutputFormat) -> Result<(), Error> {
+    let url = to_switches_url(server_addr);
+    if debug {
+        eprintln!("[debug] GET {url}");
+    }
+
+    let mut req = client.get(&url);
+    if let Some(token) = token {
+        req = req.bearer_auth(token);
+    }
+
+    let resp = req.send()?;
+
     if !resp.status().is_success() {
         handle_bad_status_code(resp)
     }
     else {
-        let resp: SwitchesResponse = serde_json::from_str(&resp.text()?)
+        let status = resp.status();
+        let text = resp.text()?;
+
+        if debug {
+            eprintln!("[debug] status: {status}");
+            eprintln!("[debug] body: {text}");
+        }
+
+        let resp: SwitchesResponse = serde_json::from_str(&text)
             .map_err(|err| anyhow!("Failed to parse response: {}", err))?;
 
-        println!("Switch list:");
-        for switch in resp.switches {
-            println!("    {switch}");
+        match output {
+            OutputFormat::Human => {
+                println!("Switch list:");
+                for switch in resp.switches {
+                    println!("    {switch}");
+                }
+            }
+            OutputFormat::Json => println!("{}", serde_json::to_string(&resp.switches)?)
         }
 
         Ok(())
     }
 }
 
+fn check_health(client: Client, server_addr: String, token: Option<String>, debug: bool, output: OutputFormat) -> Result<(), Error> {
+    let url = format!("{server_addr}api/health");
+    if debug {
+        eprintln!("[debug] GET {url}");
+    }
+
+    let mut req = client.get(&url);
+    if let Some(token) = token {
+        req = req.bearer_auth(token);
+    }
+
+    let resp = req.send()?;
+    let status = resp.status();
+
+    if debug {
+        eprintln!("[debug] status: {status}");
+    }
+
+    if status.is_success() {
+        match output {
+            OutputFormat::Human => println!("server is up ({status})"),
+            OutputFormat::Json => {
+                let health = HealthOutput { up: true, status: status.as_u16() };
+                println!("{}", serde_json::to_string(&health)?)
+            }
+        }
+        Ok(())
+    }
+    else {
+        Err(StatusError { message: format!("server is not up ({status})"), status: Some(status.as_u16()) }.into())
+    }
+}
+
+fn watch_switch(client: Client, server_addr: String, switch_name: String, interval_ms: u64, timeout_secs: Option<u64>, token: Option<String>, debug: bool, output: OutputFormat) -> Result<(), Error> {
+    let interval = Duration::from_millis(interval_ms);
+    let deadline = timeout_secs.map(|secs| Instant::now() + Duration::from_secs(secs));
+
+    let mut last_seen: Option<(String, String)> = None;
+
+    loop {
+        let url = to_switch_url(server_addr.clone(), switch_name.clone());
+        if debug {
+            eprintln!("[debug] GET {url}");
+        }
+
+        let mut req = client.get(&url);
+        if let Some(token) = &token {
+            req = req.bearer_auth(token);
+        }
+
+        let resp = req.send()?;
+
+        if !resp.status().is_success() {
+            return handle_bad_status_code(resp);
+        }
+
+        let status = resp.status();
+        let text = resp.text()?;
+
+        if debug {
+            eprintln!("[debug] status: {status}");
+            eprintln!("[debug] body: {text}");
+        }
+
+        let state: SwitchStateResponse = serde_json::from_str(&text)
+            .map_err(|err| anyhow!("Failed to parse response: {}", err))?;
+
+        let current = (state.state, state.transitioning);
+        if last_seen.as_ref() != Some(&current) {
+            match output {
+                OutputFormat::Human => println!("state = {}, transitioning = {}", current.0, current.1),
+                OutputFormat::Json => {
+                    let state = SwitchStateResponse { state: current.0.clone(), transitioning: current.1.clone() };
+                    println!("{}", serde_json::to_string(&state)?)
+                }
+            }
+            last_seen = Some(current);
+        }
+
+        if last_seen.as_ref().map(|(_, transitioning)| transitioning == "false").unwrap_or(false) {
+            return Ok(());
+        }
+
+        if let Some(deadline) = deadline {
+            if Instant::now() >= deadline {
+                bail!("timed out waiting for switch \"{}\" to settle", switch_name);
+            }
+        }
+
+        thread::sleep(interval);
+    }
+}
+
 fn fix_server_addr(mut server_addr: String) -> String {
     if !server_addr.starts_with("http://") {
         server_addr = format!("http://{server_addr}")
@@ -188,15 +549,18 @@ fn to_switches_url(server_addr: String) -> String {
 
 fn handle_bad_status_code(resp: Response) -> Result<(), Error> {
     let status_code = resp.status();
-    if let Ok(text) = resp.text() {
-        match get_error_message(&text) {
-            Ok(err_msg) => bail!("{} response: {}", status_code, err_msg),
-            Err(err) => bail!("Could not retrieve error message for {} response: {}", status_code, err)
-        };
-    }
-    else {
-        bail!("Failed to get text body from response")
-    }
+    let text = resp.text()
+        .map_err(|_| anyhow!("Failed to get text body from response"))?;
+
+    let message = match get_error_message(&text) {
+        Ok(err_msg) => err_msg,
+        Err(err) => format!("Could not retrieve error message for {status_code} response: {err}")
+    };
+
+    Err(StatusError {
+        message: format!("{status_code} response: {message}"),
+        status: Some(status_code.as_u16())
+    }.into())
 }
 
 fn get_error_message(resp_text: &str) -> Result<String, Error> {